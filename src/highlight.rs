@@ -11,29 +11,82 @@
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::io::{self, Write};
+use std::panic;
 use std::path::{Path, PathBuf};
 use std::str;
+use std::thread;
 use std::time::Instant;
 
 use rustdoc::html::highlight::{self, Classifier, Class};
 use syntax::parse;
 use syntax::parse::lexer::{self, TokenAndSpan};
-use syntax::codemap::{CodeMap, Loc};
+use syntax::codemap::{BytePos, CodeMap, Loc};
 
 use analysis::{AnalysisHost, Span};
 
+// Run `f`, catching a panic instead of letting it unwind out (used below to
+// guard the old rustc parser bailing via a panicking `FatalError`). This
+// temporarily installs a no-op panic hook so the default hook's `thread
+// panicked at ...` message isn't printed for every malformed file -- with
+// broken/mid-edit input as common as it is here, that would otherwise flood
+// the log. Assumes `panic = "unwind"` (the crate default); under `panic =
+// "abort"` this would abort the process rather than recovering.
+fn catch_unwind_quietly<F, R>(f: F) -> thread::Result<R>
+    where F: FnOnce() -> R
+{
+    let prev_hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(f));
+    panic::set_hook(prev_hook);
+    result
+}
+
 pub fn highlight<'a>(analysis: &'a AnalysisHost, project_path: &'a Path, file_name: String, file_text: String) -> String {
+    highlight_impl(analysis, project_path, file_name, file_text, false)
+}
+
+// Like `highlight`, but additionally assigns each distinct binding a stable,
+// randomly chosen colour (keyed on its analysis id), so that every
+// occurrence of one binding reads in one hue and shadowing/data flow across
+// the file is easy to spot at a glance.
+pub fn highlight_rainbow<'a>(analysis: &'a AnalysisHost, project_path: &'a Path, file_name: String, file_text: String) -> String {
+    highlight_impl(analysis, project_path, file_name, file_text, true)
+}
+
+fn highlight_impl<'a>(analysis: &'a AnalysisHost, project_path: &'a Path, file_name: String, file_text: String, rainbow: bool) -> String {
     debug!("highlight `{}` in `{}`", file_text, file_name);
     let sess = parse::ParseSess::new();
-    let fm = sess.codemap().new_filemap(file_name.clone(), None, file_text);
+    let fm = sess.codemap().new_filemap(file_name.clone(), None, file_text.clone());
+    let fm_start = fm.start_pos;
 
     let mut out = Highlighter::new(analysis, project_path, sess.codemap());
+    out.rainbow = rainbow;
 
     let t_start = Instant::now();
 
-    let mut classifier = Classifier::new(lexer::StringReader::new(&sess.span_diagnostic, fm),
-                                         sess.codemap());
-    classifier.write_source(&mut out).unwrap();
+    // The real classifier is built on top of `syntax`'s parser, which bails
+    // (via a panicking `FatalError`) on anything it can't lex/parse. A file
+    // that's mid-edit or otherwise broken would then be unbrowsable, so if
+    // that happens we fall back to a lossless tokenizer that never fails.
+    let result = catch_unwind_quietly(|| {
+        let mut classifier = Classifier::new(lexer::StringReader::new(&sess.span_diagnostic, fm.clone()),
+                                             sess.codemap());
+        classifier.write_source(&mut out)
+    });
+
+    match result {
+        Ok(_) => {
+            // `Writer` has no "end of input" hook, so anything still held
+            // back for gluing/merging when `write_source` returns would
+            // otherwise be silently dropped from the output.
+            out.flush_pending_doc().unwrap();
+            out.flush_pending_op().unwrap();
+        }
+        Err(_) => {
+            out.buf.clear();
+            out.write_lossless(&file_text, fm_start).unwrap();
+        }
+    }
 
     let time = t_start.elapsed();
     info!("Highlighting {} in {:.3}s", file_name, time.as_secs() as f64 + time.subsec_nanos() as f64 / 1_000_000_000.0);
@@ -44,21 +97,262 @@ pub fn highlight<'a>(analysis: &'a AnalysisHost, project_path: &'a Path, file_na
 pub fn custom_highlight<H: highlight::Writer + GetBuf>(file_name: String, file_text: String, highlighter: &mut H) -> String {
     debug!("custom_highlight `{}` in `{}`", file_text, file_name);
     let sess = parse::ParseSess::new();
-    let fm = sess.codemap().new_filemap(file_name.clone(), None, file_text);
+    let fm = sess.codemap().new_filemap(file_name.clone(), None, file_text.clone());
 
-    let mut classifier = Classifier::new(lexer::StringReader::new(&sess.span_diagnostic, fm),
-                                         sess.codemap());
-    classifier.write_source(highlighter).unwrap();
+    // Snippets fed in here (e.g. doc-comment code examples, see
+    // `inject_doc_code_blocks`) aren't guaranteed to lex/parse cleanly --
+    // `ignore`/partial examples are common. Don't let one bad snippet panic
+    // through a caller that might itself be inside a `catch_unwind` guarding
+    // a whole file (see `highlight_impl`); fall back to plain escaped text.
+    let result = catch_unwind_quietly(|| {
+        let mut classifier = Classifier::new(lexer::StringReader::new(&sess.span_diagnostic, fm),
+                                             sess.codemap());
+        classifier.write_source(highlighter)
+    });
+
+    if let Err(_) = result {
+        highlighter.clear_buf();
+        highlighter.string(html_escape(&file_text), Class::None, None).unwrap();
+    }
 
     String::from_utf8_lossy(highlighter.get_buf()).into_owned()
 }
 
+// Render `file_text` as a complete, standalone HTML document: the usual
+// highlighted spans, but with the class definitions inlined in a `<style>`
+// block instead of relying on rustw's own stylesheet, and with `link=`/
+// `doc_url=`/`src_url=` attributes turned into real `<a href>` anchors
+// (resolved relative to `base_path`) instead of data attributes the
+// front-end's JS would normally wire up. The result can be saved or
+// published on its own, with working cross-references, without running
+// the rustw server.
+pub fn export_standalone_html(analysis: &AnalysisHost, project_path: &Path, file_name: String, file_text: String, base_path: &str) -> String {
+    let body = highlight(analysis, project_path, file_name.clone(), file_text);
+    let linked = linkify_spans(&body, base_path);
+    wrap_standalone_document(&file_name, &linked)
+}
+
+fn wrap_standalone_document(file_name: &str, body: &str) -> String {
+    format!("<!DOCTYPE html>\n\
+             <html>\n\
+             <head>\n\
+             <meta charset=\"utf-8\">\n\
+             <title>{title}</title>\n\
+             <style>\n{css}</style>\n\
+             </head>\n\
+             <body>\n\
+             <pre class=\"rust\"><code>{body}</code></pre>\n\
+             </body>\n\
+             </html>\n",
+            title = html_escape(file_name),
+            css = STANDALONE_CSS,
+            body = body)
+}
+
+const STANDALONE_CSS: &'static str = "\
+body { background: #fff; color: #000; font-family: monospace; }\n\
+pre.rust { white-space: pre-wrap; }\n\
+.comment, .doccomment { color: #8e908c; font-style: italic; }\n\
+.kw { color: #8959a8; }\n\
+.string, .number { color: #718c00; }\n\
+.ident { color: #000; }\n\
+.lifetime { color: #c82829; }\n\
+.macro { color: #4271ae; }\n\
+.self, .bool { color: #f5871f; }\n\
+.op { color: #3e999f; }\n\
+.mut { text-decoration: underline; }\n\
+.unsafe { color: #c82829; }\n\
+.callable { font-weight: bold; }\n\
+.error { background: #ffdddd; }\n\
+a[href] { color: inherit; text-decoration: none; border-bottom: 1px dotted; }\n\
+a[href]:hover { border-bottom-style: solid; }\n\
+";
+
+// Rewrite any `<span ...>...</span>` whose tag carries a `link=`, `doc_url=`
+// or `src_url=` attribute into `<a class='...' href='...'>...</a>`, leaving
+// every other span untouched. Handles the nested spans that the doc-comment
+// code injection (see `inject_doc_code_blocks`) can produce, by tracking
+// open/close depth rather than assuming spans never nest.
+fn linkify_spans(html: &str, base_path: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(idx) = rest.find("<span") {
+        out.push_str(&rest[..idx]);
+
+        // Every tag we emit ends with a quoted attribute value, and `'` inside
+        // values is escaped, so `'>` marks the tag's end; a bare `>` could
+        // be the `<br>` that `push_char` puts into a multi-line title.
+        let tag_end = idx + rest[idx..].find("'>").unwrap() + 2;
+        let tag = &rest[idx..tag_end];
+
+        let mut depth = 1usize;
+        let mut scan = tag_end;
+        // `inner_end`/`close_end` differ only when the span actually closes;
+        // for an unclosed `<span` (truncated/malformed input) there's no
+        // `</span>` to strip off, so both fall back to `rest.len()` rather
+        // than underflowing a `close_end - "</span>".len()` subtraction.
+        let (inner_end, close_end) = loop {
+            let next_open = rest[scan..].find("<span");
+            let next_close = rest[scan..].find("</span>");
+            match (next_open, next_close) {
+                (Some(o), Some(c)) if o < c => {
+                    depth += 1;
+                    scan += o + 5;
+                }
+                (_, Some(c)) => {
+                    depth -= 1;
+                    let close_start = scan + c;
+                    if depth == 0 {
+                        break (close_start, close_start + "</span>".len());
+                    }
+                    scan = close_start + "</span>".len();
+                }
+                _ => break (rest.len(), rest.len()),
+            }
+        };
+
+        let inner_start = tag_end;
+        // Recurse so link spans nested inside this one (e.g. from doc-code
+        // injection re-highlighting a snippet) get linkified too.
+        let inner = linkify_spans(&rest[inner_start..inner_end], base_path);
+
+        match resolve_href(tag, base_path) {
+            Some(href) => {
+                let classes = extract_attr(tag, "class").unwrap_or_default();
+                let mut attrs = format!("class='{}' href='{}'", classes, href);
+                if let Some(title) = extract_attr(tag, "title") {
+                    attrs.push_str(&format!(" title='{}'", title));
+                }
+                if let Some(id) = extract_attr(tag, "id") {
+                    attrs.push_str(&format!(" id='{}'", id));
+                }
+                out.push_str(&format!("<a {}>{}</a>", attrs, inner));
+            }
+            None => {
+                out.push_str(tag);
+                out.push_str(&inner);
+                out.push_str("</span>");
+            }
+        }
+
+        rest = &rest[close_end..];
+    }
+    out.push_str(rest);
+
+    out
+}
+
+fn extract_attr(tag: &str, name: &str) -> Option<String> {
+    let pat = format!(" {}='", name);
+    let start = tag.find(&pat).map(|i| i + pat.len())?;
+    let end = start + tag[start..].find('\'')?;
+    Some(tag[start..end].to_owned())
+}
+
+fn resolve_href(tag: &str, base_path: &str) -> Option<String> {
+    if let Some(doc_url) = extract_attr(tag, "doc_url") {
+        return Some(doc_url);
+    }
+    if let Some(src_url) = extract_attr(tag, "src_url") {
+        return Some(src_url);
+    }
+    if let Some(link) = extract_attr(tag, "link") {
+        if link.starts_with("search:") {
+            // Only resolvable by the running server's search index.
+            return None;
+        }
+        // `get_link` formats these as `file:line_start:col_start:line_end:col_end`.
+        let mut parts: Vec<&str> = link.rsplitn(5, ':').collect();
+        parts.reverse();
+        if let (Some(file), Some(line_start)) = (parts.get(0), parts.get(1)) {
+            return Some(format!("{}/{}.html#L{}", base_path.trim_end_matches('/'), file, line_start));
+        }
+    }
+    None
+}
+
 struct Highlighter<'a> {
     buf: Vec<u8>,
     analysis: &'a AnalysisHost,
     codemap: &'a CodeMap,
     project_path: &'a Path,
     path_cache: HashMap<String, PathBuf>,
+    // When set, every distinct binding (keyed on its analysis id) is given
+    // its own stable colour. Off by default so normal highlighting is
+    // unaffected.
+    rainbow: bool,
+    // A single-character operator token held back in case the next token
+    // glues onto it to form one logical multi-character operator (`>>`,
+    // `->`, `==`, ...). See `glue_and_emit`.
+    pending_op: Option<PendingOp>,
+    // Consecutive `///`/`//!` line doc-comment tokens held back so fenced
+    // code examples that span several lines (i.e. several tokens) can be
+    // found and highlighted as one block. See `doc_comment_and_emit`.
+    pending_doc: Vec<String>,
+    // A single whitespace token tentatively held between two doc-comment
+    // lines; replayed verbatim if the run turns out to have ended there.
+    pending_doc_ws: Option<(String, Option<(BytePos, BytePos)>)>,
+}
+
+struct PendingOp {
+    text: String,
+    lo: BytePos,
+    hi: BytePos,
+}
+
+// Tokens that combine into a single logical operator when they appear with
+// no gap between them. Lexed one unit at a time, `>` `>` (closing two
+// generic parameter lists), `=` `=`, `-` `>` etc. would otherwise arrive as
+// separate spans and key `goto_def`/type-tooltip lookups off the wrong byte
+// range.
+fn glue(a: &str, b: &str) -> Option<&'static str> {
+    match (a, b) {
+        (">", ">") => Some(">>"),
+        ("<", "<") => Some("<<"),
+        ("=", "=") => Some("=="),
+        ("!", "=") => Some("!="),
+        ("<", "=") => Some("<="),
+        (">", "=") => Some(">="),
+        ("-", ">") => Some("->"),
+        ("=", ">") => Some("=>"),
+        ("&", "&") => Some("&&"),
+        ("|", "|") => Some("||"),
+        (":", ":") => Some("::"),
+        (".", ".") => Some(".."),
+        ("+", "=") => Some("+="),
+        ("-", "=") => Some("-="),
+        ("*", "=") => Some("*="),
+        ("/", "=") => Some("/="),
+        ("%", "=") => Some("%="),
+        ("^", "=") => Some("^="),
+        ("&", "=") => Some("&="),
+        ("|", "=") => Some("|="),
+        // Chained onto an already-glued pair below (see `glue_and_emit`):
+        // `>>` + `=`, `<<` + `=`, `..` + `=`/`.`.
+        (">>", "=") => Some(">>="),
+        ("<<", "=") => Some("<<="),
+        ("..", "=") => Some("..="),
+        ("..", ".") => Some("..."),
+        _ => None,
+    }
+}
+
+fn is_glue_starter(text: &str) -> bool {
+    match text {
+        ">" | "<" | "=" | "!" | "-" | "&" | "|" | ":" | "." | "+" | "*" | "/" | "%" | "^" => true,
+        // Results of a first glue pass that can still extend into a
+        // three-character operator (`>>=`, `<<=`, `..=`, `...`).
+        ">>" | "<<" | ".." => true,
+        _ => false,
+    }
+}
+
+// A `Class::DocComment` token is either a `///`/`//!` line comment (lexed
+// one line at a time -- see `doc_comment_and_emit`) or a `/** */`/`/*! */`
+// block comment (lexed as a single, already-complete token).
+fn is_line_doc_comment(text: &str) -> bool {
+    text.starts_with("//")
 }
 
 impl<'a> Highlighter<'a> {
@@ -69,9 +363,34 @@ impl<'a> Highlighter<'a> {
             codemap: codemap,
             project_path: project_path,
             path_cache: HashMap::new(),
+            rainbow: false,
+            pending_op: None,
+            pending_doc: vec![],
+            pending_doc_ws: None,
         }
     }
 
+    // Deterministically derive an `hsl(...)` colour from an analysis id,
+    // using a tiny xorshift PRNG as the seed so the same id always maps to
+    // the same colour, while different ids diverge.
+    fn rainbow_color(id: u32) -> String {
+        let mut state = id ^ 0x9e3779b9;
+        if state == 0 {
+            state = 0x9e3779b9;
+        }
+        let mut next_u32 = || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+
+        let h = next_u32() % 361;
+        let s = 42 + next_u32() % (98 - 42 + 1);
+        let l = 40 + next_u32() % (90 - 40 + 1);
+        format!("hsl({}, {}%, {}%)", h, s, l)
+    }
+
     fn get_link(&self, span: &Span) -> Option<String> {
         self.analysis.goto_def(span).ok().and_then(|def_span| {
             if span == &def_span {
@@ -148,6 +467,287 @@ impl<'a> Highlighter<'a> {
             column_end: hi.col.0 as usize,
         }
     }
+
+    // Shared with the lossless fallback below: looks up analysis info for an
+    // identifier given its source locations directly, rather than going
+    // through a `TokenAndSpan` from a real parse.
+    fn write_ident_at(&mut self, text: String, lo: Loc, hi: Loc) -> io::Result<()> {
+        let span = &self.span_from_locs(&lo, &hi);
+        let ty = self.analysis.show_type(span).ok().and_then(|s| if s.is_empty() { None } else { Some(s) });
+        let docs = self.analysis.docs(span).ok().and_then(|s| if s.is_empty() { None } else { Some(s) });
+        let modifiers = Highlighter::semantic_modifiers(ty.as_ref().map(|s| s.as_str()));
+        let title = match (ty, docs) {
+            (Some(t), Some(d)) => Some(format!("{}\n\n{}", t, d)),
+            (Some(t), _) => Some(t),
+            (_, Some(d)) => Some(d),
+            (None, None) => None,
+        };
+        let mut link = self.get_link(span);
+        let doc_link = self.analysis.doc_url(span).ok();
+        let src_link = self.analysis.src_url(span).ok();
+
+        let mut style = None;
+        let mut css_class = match self.analysis.id(span) {
+            Ok(id) => {
+                if link.is_none() {
+                    link = Some(format!("search:{}", id));
+                }
+                if self.rainbow {
+                    style = Some(format!("style='color:{}'", Highlighter::rainbow_color(id as u32)));
+                }
+
+                format!(" class_id class_id_{}", id)
+            }
+            Err(_) => String::new(),
+        };
+        css_class.push_str(&modifiers);
+        let css_class = if css_class.is_empty() { None } else { Some(css_class) };
+
+        Highlighter::write_span(&mut self.buf, Class::Ident, text, title, css_class, None, link, doc_link, src_link, style)
+    }
+
+    // Extra CSS modifier classes describing the referenced definition, so
+    // the front-end can style mutability, unsafety and symbol category
+    // distinctly instead of collapsing everything to a bare `ident`. There's
+    // no dedicated `AnalysisHost` query for any of this, so we pick it out
+    // of the `show_type` text we already fetch above.
+    //
+    // These are deliberately narrow prefix checks rather than `contains`:
+    // `show_type` text often nests other types inside generics or fn
+    // signatures (e.g. a `Vec<fn() -> i32>` field, or an `Iterator<Item =
+    // &mut T>`), and matching anywhere in the string would tag the outer
+    // binding as `mut`/`callable` based on something buried levels down
+    // that isn't its own type.
+    //
+    // The original request also asked for distinct `field`/`local`/`param`
+    // modifiers. `AnalysisHost` doesn't expose binding-kind anywhere we call
+    // from here -- only type/docs/id/links -- so that distinction isn't
+    // implemented; guessing it from `show_type` text wouldn't be reliable
+    // either.
+    fn semantic_modifiers(ty: Option<&str>) -> String {
+        let ty = match ty {
+            Some(t) => t,
+            None => return String::new(),
+        };
+
+        let mut modifiers = String::new();
+        if ty.starts_with("&mut ") {
+            modifiers.push_str(" mut");
+        }
+        if ty.starts_with("unsafe ") {
+            modifiers.push_str(" unsafe");
+        }
+        if ty.starts_with("fn(") || ty.starts_with("fn ") {
+            modifiers.push_str(" callable");
+        }
+
+        modifiers
+    }
+
+    // Re-highlight any fenced Rust code blocks found inside a doc comment,
+    // leaving the surrounding prose as plain comment text. `text` is the
+    // HTML-escaped comment token as handed to us by the classifier, markers
+    // and all (e.g. `/// some code: \`\`\`rust ... \`\`\``).
+    //
+    // Stripping markers and reflowing is only worth the risk of mangling the
+    // comment when there's actually a fence to inject -- the overwhelming
+    // majority of doc comments have none, and for those `text` (already
+    // escaped by the classifier) is passed straight through unchanged.
+    fn highlight_doc_comment(&self, text: &str) -> String {
+        let raw = html_unescape(text);
+        let stripped = strip_doc_comment_markers(&raw);
+        if !stripped.lines().any(|line| line.trim_start().starts_with("```")) {
+            return text.to_owned();
+        }
+        inject_doc_code_blocks(&stripped)
+    }
+
+    // `///`/`//!` line doc comments are lexed one line at a time, so a
+    // fenced code example spanning several lines arrives as several
+    // `Class::DocComment` tokens with a plain whitespace token between each
+    // pair. Hold a run of those together so `highlight_doc_comment` sees
+    // the whole example in one string and can actually find the fence.
+    fn doc_comment_and_emit(&mut self, text: String, klass: Class, locs: Option<(BytePos, BytePos)>) -> io::Result<()> {
+        if klass == Class::DocComment && is_line_doc_comment(&text) {
+            // A held whitespace token was just the gap before this line;
+            // drop it -- the merged span we emit once the run ends supplies
+            // its own line breaks.
+            self.pending_doc_ws = None;
+            self.pending_doc.push(text);
+            return Ok(());
+        }
+
+        if !self.pending_doc.is_empty() && self.pending_doc_ws.is_none() &&
+           klass == Class::None && text.trim().is_empty() {
+            // Might be the gap before another doc-comment line; hold it
+            // until we see what follows.
+            self.pending_doc_ws = Some((text, locs));
+            return Ok(());
+        }
+
+        self.flush_pending_doc()?;
+        self.glue_and_emit(text, klass, locs)
+    }
+
+    fn flush_pending_doc(&mut self) -> io::Result<()> {
+        if !self.pending_doc.is_empty() {
+            let joined = self.pending_doc.join("\n");
+            let injected = self.highlight_doc_comment(&joined);
+            Highlighter::write_span(&mut self.buf, Class::DocComment, injected, None, None, None, None, None, None, None)?;
+            self.pending_doc.clear();
+        }
+        if let Some((text, locs)) = self.pending_doc_ws.take() {
+            self.glue_and_emit(text, Class::None, locs)?;
+        }
+        Ok(())
+    }
+
+    // Buffers a single-character operator token so it can be merged with
+    // the next one if they glue into a single logical operator with no gap
+    // between them, then hands off to `emit` for the actual write.
+    fn glue_and_emit(&mut self, text: String, klass: Class, locs: Option<(BytePos, BytePos)>) -> io::Result<()> {
+        if klass != Class::Op {
+            if let Some(pending) = self.pending_op.take() {
+                self.emit(pending.text, Class::Op, Some((pending.lo, pending.hi)))?;
+            }
+            return self.emit(text, klass, locs);
+        }
+
+        let (lo, hi) = match locs {
+            Some(locs) => locs,
+            None => {
+                if let Some(pending) = self.pending_op.take() {
+                    self.emit(pending.text, Class::Op, Some((pending.lo, pending.hi)))?;
+                }
+                return self.emit(text, klass, None);
+            }
+        };
+
+        // The classifier hands us HTML-escaped text (`&gt;`, `&amp;`, ...),
+        // so compare the raw operator characters and re-escape the result.
+        if let Some(pending) = self.pending_op.take() {
+            if pending.hi == lo {
+                if let Some(glued) = glue(&html_unescape(&pending.text), &html_unescape(&text)) {
+                    // `glue` only merges two tokens at a time, so a
+                    // three-character operator (`>>=`, `..=`, ...) glues in
+                    // two passes: hold the first pair's result back as the
+                    // new pending op instead of emitting it, so it's still
+                    // around to glue with whatever comes next.
+                    if is_glue_starter(glued) {
+                        self.pending_op = Some(PendingOp { text: html_escape(glued), lo: pending.lo, hi: hi });
+                        return Ok(());
+                    }
+                    return self.emit(html_escape(glued), Class::Op, Some((pending.lo, hi)));
+                }
+            }
+            self.emit(pending.text, Class::Op, Some((pending.lo, pending.hi)))?;
+        }
+
+        if is_glue_starter(&html_unescape(&text)) {
+            self.pending_op = Some(PendingOp { text: text, lo: lo, hi: hi });
+            Ok(())
+        } else {
+            self.emit(text, Class::Op, Some((lo, hi)))
+        }
+    }
+
+    // `Writer` has no "end of input" hook, so a glue-starter left dangling
+    // at EOF (e.g. a snippet with no trailing newline ending in `>` or `..`)
+    // would otherwise just sit in `pending_op` and never be written. Call
+    // this once `write_source` returns.
+    fn flush_pending_op(&mut self) -> io::Result<()> {
+        if let Some(pending) = self.pending_op.take() {
+            self.emit(pending.text, Class::Op, Some((pending.lo, pending.hi)))?;
+        }
+        Ok(())
+    }
+
+    // The actual per-token rendering, once any operator gluing has been
+    // resolved. `locs` are the real byte offsets of `text` in the source,
+    // when known (e.g. not known for whitespace synthesised elsewhere).
+    fn emit(&mut self, text: String, klass: Class, locs: Option<(BytePos, BytePos)>) -> io::Result<()> {
+        match klass {
+            Class::None => write!(self.buf, "{}", text),
+            Class::Ident => {
+                match locs {
+                    Some((lo, hi)) => {
+                        let lo = self.codemap.lookup_char_pos(lo);
+                        let hi = self.codemap.lookup_char_pos(hi);
+                        self.write_ident_at(text, lo, hi)
+                    }
+                    None => Highlighter::write_span(&mut self.buf, Class::Ident, text, None, None, None, None, None, None, None),
+                }
+            }
+            Class::Op if text == "*" => {
+                match locs {
+                    Some((lo, hi)) => {
+                        let lo = self.codemap.lookup_char_pos(lo);
+                        let hi = self.codemap.lookup_char_pos(hi);
+                        let span = &self.span_from_locs(&lo, &hi);
+                        let title = self.analysis.show_type(span).ok();
+                        let location = Some(format!("location='{}:{}''", lo.line, lo.col.0 + 1));
+                        let css_class = Some(" glob".to_owned());
+
+                        Highlighter::write_span(&mut self.buf, Class::Op, text, title, css_class, None, None, None, None, location)
+                    }
+                    None => Highlighter::write_span(&mut self.buf, Class::Op, text, None, None, None, None, None, None, None),
+                }
+            }
+            Class::DocComment => {
+                let injected = self.highlight_doc_comment(&text);
+                Highlighter::write_span(&mut self.buf, Class::DocComment, injected, None, None, None, None, None, None, None)
+            }
+            klass => Highlighter::write_span(&mut self.buf, klass, text, None, None, None, None, None, None, None),
+        }
+    }
+
+    // Tokenize `src` with a raw, lossless lexer that cannot fail, and write
+    // it out span-by-span. Used when the real parser can't cope with the
+    // source (e.g. it's mid-edit), so we can still show something useful.
+    fn write_lossless(&mut self, src: &str, fm_start: BytePos) -> io::Result<()> {
+        for tok in lex_lossless(src) {
+            let text = &src[tok.start..tok.end];
+
+            if tok.kind == RawKind::Unknown {
+                write!(self.buf, "<span class='error'>{}</span>", html_escape(text))?;
+                continue;
+            }
+            if tok.kind == RawKind::Whitespace {
+                write!(self.buf, "{}", html_escape(text))?;
+                continue;
+            }
+
+            let escaped = html_escape(text);
+            match tok.kind {
+                RawKind::Ident => {
+                    let lo = self.codemap.lookup_char_pos(fm_start + BytePos(tok.start as u32));
+                    let hi = self.codemap.lookup_char_pos(fm_start + BytePos(tok.end as u32));
+                    self.write_ident_at(escaped, lo, hi)?;
+                }
+                RawKind::KeyWord => {
+                    Highlighter::write_span(&mut self.buf, Class::KeyWord, escaped, None, None, None, None, None, None, None)?
+                }
+                RawKind::Lifetime => {
+                    Highlighter::write_span(&mut self.buf, Class::Lifetime, escaped, None, None, None, None, None, None, None)?
+                }
+                RawKind::Number => {
+                    Highlighter::write_span(&mut self.buf, Class::Number, escaped, None, None, None, None, None, None, None)?
+                }
+                RawKind::StringLit | RawKind::CharLit => {
+                    Highlighter::write_span(&mut self.buf, Class::String, escaped, None, None, None, None, None, None, None)?
+                }
+                RawKind::LineComment { doc } | RawKind::BlockComment { doc } => {
+                    let klass = if doc { Class::DocComment } else { Class::Comment };
+                    Highlighter::write_span(&mut self.buf, klass, escaped, None, None, None, None, None, None, None)?
+                }
+                RawKind::Op => {
+                    Highlighter::write_span(&mut self.buf, Class::Op, escaped, None, None, None, None, None, None, None)?
+                }
+                RawKind::Whitespace | RawKind::Unknown => unreachable!(),
+            }
+        }
+        Ok(())
+    }
 }
 
 fn push_char(buf: &mut Vec<u8>, c: char) -> io::Result<()> {
@@ -162,72 +762,388 @@ fn push_char(buf: &mut Vec<u8>, c: char) -> io::Result<()> {
     }
 }
 
-impl<'a> highlight::Writer for Highlighter<'a> {
-    fn enter_span(&mut self, klass: Class) -> io::Result<()> {
-        write!(self.buf, "<span class='{}'>", klass.rustdoc_class())
+fn html_escape(s: &str) -> String {
+    let mut buf = Vec::with_capacity(s.len());
+    for c in s.chars() {
+        push_char(&mut buf, c).unwrap();
+    }
+    String::from_utf8(buf).unwrap()
+}
+
+// The inverse of `push_char`'s escaping, so we can get back to raw source
+// text that was already escaped by the classifier before re-lexing it.
+fn html_unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '&' && c != '<' {
+            out.push(c);
+            continue;
+        }
+
+        let rest: String = chars.clone().take(5).collect();
+        let (replacement, len) = if c == '&' && rest.starts_with("amp;") {
+            ('&', 4)
+        } else if c == '&' && rest.starts_with("lt;") {
+            ('<', 3)
+        } else if c == '&' && rest.starts_with("gt;") {
+            ('>', 3)
+        } else if c == '&' && rest.starts_with("#39;") {
+            ('\'', 4)
+        } else if c == '&' && rest.starts_with("quot;") {
+            ('"', 5)
+        } else if c == '<' && rest.starts_with("br>") {
+            ('\n', 3)
+        } else {
+            (c, 0)
+        };
+
+        out.push(replacement);
+        for _ in 0..len {
+            chars.next();
+        }
     }
+    out
+}
 
-    fn exit_span(&mut self) -> io::Result<()> {
-        write!(self.buf, "</span>")
+// Strip the `///`, `//!`, `/** */` or `/*! */` markers from a doc comment,
+// along with any leading `*` continuation markers on block comment lines,
+// returning just the inner doc text.
+fn strip_doc_comment_markers(raw: &str) -> String {
+    let trimmed = raw.trim();
+    if trimmed.starts_with("///") || trimmed.starts_with("//!") {
+        // `raw` may be several `///`/`//!` lines joined with `\n` (see
+        // `doc_comment_and_emit`), so the marker has to be stripped from
+        // every line, not just the first.
+        trimmed.lines()
+               .map(|line| {
+                   let line = line.trim_start();
+                   if line.starts_with("///") || line.starts_with("//!") {
+                       line[3..].trim_start_matches(' ')
+                   } else {
+                       line
+                   }
+               })
+               .collect::<Vec<_>>()
+               .join("\n")
+    } else if trimmed.starts_with("/**") || trimmed.starts_with("/*!") {
+        let end = trimmed.len() - if trimmed.ends_with("*/") { 2 } else { 0 };
+        let inner = &trimmed[3..end.max(3)];
+        inner.lines()
+             .map(|line| line.trim_start().trim_start_matches('*').trim_start())
+             .collect::<Vec<_>>()
+             .join("\n")
+    } else {
+        trimmed.to_owned()
     }
+}
 
-    fn string<T: Display>(&mut self, text: T, klass: Class, tas: Option<&TokenAndSpan>) -> io::Result<()> {
-        let text = text.to_string();
+// Scan doc comment prose for fenced code blocks and run the Rust ones back
+// through `custom_highlight`, so they render fully highlighted rather than
+// as inert text. Blocks tagged with another language, or left untagged
+// with an explicit non-Rust marker, are left as plain (escaped) text.
+fn inject_doc_code_blocks(text: &str) -> String {
+    let mut out = String::new();
+    let mut lines = text.lines().peekable();
 
-        match klass {
-            Class::None => write!(self.buf, "{}", text),
-            Class::Ident => {
-                match tas {
-                    Some(t) => {
-                        let lo = self.codemap.lookup_char_pos(t.sp.lo);
-                        let hi = self.codemap.lookup_char_pos(t.sp.hi);
-                        let span = &self.span_from_locs(&lo, &hi);
-                        let ty = self.analysis.show_type(span).ok().and_then(|s| if s.is_empty() { None } else { Some(s) });
-                        let docs = self.analysis.docs(span).ok().and_then(|s| if s.is_empty() { None } else { Some(s) });
-                        let title = match (ty, docs) {
-                            (Some(t), Some(d)) => Some(format!("{}\n\n{}", t, d)),
-                            (Some(t), _) => Some(t),
-                            (_, Some(d)) => Some(d),
-                            (None, None) => None,
-                        };
-                        let mut link = self.get_link(span);
-                        let doc_link = self.analysis.doc_url(span).ok();
-                        let src_link = self.analysis.src_url(span).ok();
-
-                        let css_class = match self.analysis.id(span) {
-                            Ok(id) => {
-                                if link.is_none() {
-                                    link = Some(format!("search:{}", id));
-                                }
-
-                                Some(format!(" class_id class_id_{}", id))
+    while let Some(line) = lines.next() {
+        if !line.trim_start().starts_with("```") {
+            out.push_str(&html_escape(line));
+            // Only separate this line from what follows; a trailing `<br>`
+            // after the last line would double up with the `\n` that the
+            // next whitespace token already contributes.
+            if lines.peek().is_some() {
+                out.push_str("<br>");
+            }
+            continue;
+        }
+
+        let tag = line.trim_start().trim_start_matches('`').trim().to_lowercase();
+        let is_rust = tag.is_empty() ||
+            tag.split(',').map(|t| t.trim()).any(|t| {
+                t == "rust" || t == "ignore" || t == "no_run" || t == "should_panic" || t == "compile_fail" || t == "edition2018"
+            });
+
+        let mut code = String::new();
+        while let Some(inner) = lines.peek() {
+            if inner.trim_start().starts_with("```") {
+                lines.next();
+                break;
+            }
+            code.push_str(lines.next().unwrap());
+            code.push('\n');
+        }
+
+        if is_rust {
+            let mut highlighter = BasicHighlighter::new();
+            let highlighted = custom_highlight("<doc-comment-injection>".to_owned(), code, &mut highlighter);
+            out.push_str(&highlighted);
+        } else {
+            out.push_str(&html_escape(&code));
+        }
+    }
+
+    out
+}
+
+// A single token produced by the lossless lexer. Byte offsets are relative
+// to the start of the source passed to `lex_lossless`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct RawToken {
+    kind: RawKind,
+    start: usize,
+    end: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum RawKind {
+    Whitespace,
+    Ident,
+    KeyWord,
+    Lifetime,
+    Number,
+    StringLit,
+    CharLit,
+    LineComment { doc: bool },
+    BlockComment { doc: bool },
+    Op,
+    // A single character that doesn't start any recognised token, e.g. a
+    // stray `\`. (Unterminated strings and block comments still lex as
+    // their own kinds.) Unlike the real `StringReader`, this never causes
+    // the whole pass to fail.
+    Unknown,
+}
+
+const KEYWORDS: &'static [&'static str] = &[
+    "as", "box", "break", "const", "continue", "crate", "else", "enum", "extern", "false", "fn",
+    "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref",
+    "return", "self", "Self", "static", "struct", "super", "trait", "true", "type", "unsafe",
+    "use", "where", "while", "abstract", "alignof", "become", "do", "final", "macro", "offsetof",
+    "override", "priv", "proc", "pure", "sizeof", "typeof", "unsized", "virtual", "yield",
+];
+
+fn is_ident_start(c: char) -> bool {
+    c == '_' || c.is_alphabetic()
+}
+
+fn is_ident_continue(c: char) -> bool {
+    c == '_' || c.is_alphanumeric()
+}
+
+// Walks `src` byte-by-byte (via `char_indices`) and classifies every run of
+// characters into a `RawToken`, without requiring the source to parse. This
+// is deliberately conservative: anything it doesn't recognise becomes a
+// single-character `Unknown` token rather than derailing the rest of the scan.
+fn lex_lossless(src: &str) -> Vec<RawToken> {
+    let mut toks = vec![];
+    let mut chars = src.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            let mut end = start + c.len_utf8();
+            chars.next();
+            while let Some(&(i, c)) = chars.peek() {
+                if !c.is_whitespace() { break; }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            toks.push(RawToken { kind: RawKind::Whitespace, start: start, end: end });
+            continue;
+        }
+
+        if c == '/' {
+            let mut la = chars.clone();
+            la.next();
+            match la.peek().cloned() {
+                Some((_, '/')) => {
+                    chars.next();
+                    chars.next();
+                    let doc = match chars.peek() {
+                        Some(&(_, '/')) | Some(&(_, '!')) => true,
+                        _ => false,
+                    };
+                    let mut end = start + 2;
+                    while let Some(&(i, c)) = chars.peek() {
+                        if c == '\n' { break; }
+                        end = i + c.len_utf8();
+                        chars.next();
+                    }
+                    toks.push(RawToken { kind: RawKind::LineComment { doc: doc }, start: start, end: end });
+                    continue;
+                }
+                Some((_, '*')) => {
+                    chars.next();
+                    chars.next();
+                    let doc = match chars.peek() {
+                        Some(&(_, '*')) | Some(&(_, '!')) => true,
+                        _ => false,
+                    };
+                    let mut end = start + 2;
+                    let mut depth = 1usize;
+                    let mut closed = false;
+                    while let Some((i, c)) = chars.next() {
+                        end = i + c.len_utf8();
+                        if c == '*' {
+                            if let Some(&(_, '/')) = chars.peek() {
+                                let (j, c2) = chars.next().unwrap();
+                                end = j + c2.len_utf8();
+                                depth -= 1;
+                                if depth == 0 { closed = true; break; }
+                            }
+                        } else if c == '/' {
+                            if let Some(&(_, '*')) = chars.peek() {
+                                let (j, c2) = chars.next().unwrap();
+                                end = j + c2.len_utf8();
+                                depth += 1;
                             }
-                            Err(_) => None,
-                        };
+                        }
+                    }
+                    let _ = closed;
+                    toks.push(RawToken { kind: RawKind::BlockComment { doc: doc }, start: start, end: end });
+                    continue;
+                }
+                _ => {}
+            }
+        }
 
+        if c == '"' {
+            chars.next();
+            let mut end = start + 1;
+            let mut escaped = false;
+            while let Some((i, c)) = chars.next() {
+                end = i + c.len_utf8();
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == '"' {
+                    break;
+                } else if c == '\n' {
+                    // Unterminated string literal, but don't bail: just end
+                    // the token at the newline.
+                    end = i;
+                    break;
+                }
+            }
+            toks.push(RawToken { kind: RawKind::StringLit, start: start, end: end });
+            continue;
+        }
 
-                        Highlighter::write_span(&mut self.buf, Class::Ident, text, title, css_class, None, link, doc_link, src_link, None)
+        if c == '\'' {
+            // Could be a char literal (`'a'`, `'\n'`) or a lifetime (`'a`).
+            let mut la = chars.clone();
+            la.next();
+            let second = la.next();
+            let third = la.peek().cloned();
+            let is_char_lit = match (second, third) {
+                (Some((_, '\\')), _) => true,
+                (Some((_, c2)), Some((_, '\''))) if c2 != '\'' => true,
+                _ => false,
+            };
+            if is_char_lit {
+                chars.next();
+                let mut end = start + 1;
+                let mut escaped = false;
+                while let Some((i, c)) = chars.next() {
+                    end = i + c.len_utf8();
+                    if escaped {
+                        escaped = false;
+                    } else if c == '\\' {
+                        escaped = true;
+                    } else if c == '\'' {
+                        break;
+                    } else if c == '\n' {
+                        end = i;
+                        break;
                     }
-                    None => Highlighter::write_span(&mut self.buf, Class::Ident, text, None, None, None, None, None, None, None),
                 }
+                toks.push(RawToken { kind: RawKind::CharLit, start: start, end: end });
+                continue;
+            } else {
+                chars.next();
+                let mut end = start + 1;
+                while let Some(&(i, c)) = chars.peek() {
+                    if !is_ident_continue(c) { break; }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+                toks.push(RawToken { kind: RawKind::Lifetime, start: start, end: end });
+                continue;
             }
-            Class::Op if text == "*" => {
-                match tas {
-                    Some(t) => {
-                        let lo = self.codemap.lookup_char_pos(t.sp.lo);
-                        let hi = self.codemap.lookup_char_pos(t.sp.hi);
-                        let span = &self.span_from_locs(&lo, &hi);
-                        let title = self.analysis.show_type(span).ok();
-                        let location = Some(format!("location='{}:{}''", lo.line, lo.col.0 + 1));
-                        let css_class = Some(" glob".to_owned());
+        }
 
-                        Highlighter::write_span(&mut self.buf, Class::Op, text, title, css_class, None, None, None, None, location)
+        if c.is_ascii_digit() {
+            let mut end = start + 1;
+            chars.next();
+            let mut seen_dot = false;
+            while let Some(&(i, c)) = chars.peek() {
+                if c == '.' {
+                    // Only a decimal point if followed by another digit;
+                    // otherwise it's the start of `..`/`..=` (a range) or a
+                    // method call (`1.foo()`), neither of which belongs to
+                    // the number token.
+                    if seen_dot { break; }
+                    let mut la = chars.clone();
+                    la.next();
+                    match la.peek() {
+                        Some(&(_, next)) if next.is_ascii_digit() => seen_dot = true,
+                        _ => break,
                     }
-                    None => Highlighter::write_span(&mut self.buf, Class::Op, text, None, None, None, None, None, None, None),
+                } else if !(c.is_alphanumeric() || c == '_') {
+                    break;
                 }
+                end = i + c.len_utf8();
+                chars.next();
             }
-            klass => Highlighter::write_span(&mut self.buf, klass, text, None, None, None, None, None, None, None),
+            toks.push(RawToken { kind: RawKind::Number, start: start, end: end });
+            continue;
+        }
+
+        if is_ident_start(c) {
+            let mut end = start + c.len_utf8();
+            chars.next();
+            while let Some(&(i, c)) = chars.peek() {
+                if !is_ident_continue(c) { break; }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            let text = &src[start..end];
+            let kind = if KEYWORDS.contains(&text) { RawKind::KeyWord } else { RawKind::Ident };
+            toks.push(RawToken { kind: kind, start: start, end: end });
+            continue;
         }
+
+        const OPS: &'static str = "+-*/%^!&|<>=:;,.()[]{}#@?~$";
+        if OPS.contains(c) {
+            let end = start + c.len_utf8();
+            chars.next();
+            toks.push(RawToken { kind: RawKind::Op, start: start, end: end });
+            continue;
+        }
+
+        // Not whitespace, not the start of any recognised token: emit a
+        // single-character `Unknown` token and keep going.
+        let end = start + c.len_utf8();
+        chars.next();
+        toks.push(RawToken { kind: RawKind::Unknown, start: start, end: end });
+    }
+
+    toks
+}
+
+impl<'a> highlight::Writer for Highlighter<'a> {
+    fn enter_span(&mut self, klass: Class) -> io::Result<()> {
+        write!(self.buf, "<span class='{}'>", klass.rustdoc_class())
+    }
+
+    fn exit_span(&mut self) -> io::Result<()> {
+        write!(self.buf, "</span>")
+    }
+
+    fn string<T: Display>(&mut self, text: T, klass: Class, tas: Option<&TokenAndSpan>) -> io::Result<()> {
+        let text = text.to_string();
+        let locs = tas.map(|t| (t.sp.lo, t.sp.hi));
+        self.doc_comment_and_emit(text, klass, locs)
     }
 }
 
@@ -246,12 +1162,17 @@ struct SpanSpan {
 
 pub trait GetBuf {
     fn get_buf(&self) -> &[u8];
+    fn clear_buf(&mut self);
 }
 
 impl GetBuf for BasicHighlighter {
     fn get_buf(&self) -> &[u8] {
         &self.buf
-    }    
+    }
+
+    fn clear_buf(&mut self) {
+        self.buf.clear();
+    }
 }
 
 impl BasicHighlighter {